@@ -3,6 +3,7 @@ use std::{
     io::{stdin, stdout, BufRead, Read, Write},
     path::PathBuf,
     str::FromStr,
+    sync::Arc,
 };
 
 use serde_json::{json, Value};
@@ -12,30 +13,97 @@ use memmap2::Mmap;
 
 use anyhow::Result;
 
+/// Feature-field schema for a loaded dictionary's `feature()` string.
+///
+/// Different dictionary formats pack different columns into that
+/// hyphen/comma-separated string, so the field names can't be hard-coded
+/// once for every dictionary the way the original single-dictionary worker
+/// did.
+fn feature_schema(dict_name: &str) -> &'static [&'static str] {
+    match dict_name {
+        "ipadic" | "ipadic-neologd" => &[
+            "pos",
+            "pos2",
+            "pos3",
+            "pos4",
+            "inflection_type",
+            "inflection_form",
+            "lemma",
+            "reading",
+            "pronunciation",
+        ],
+        _ => &[
+            "pos",
+            "pos2",
+            "pos3",
+            "pos4",
+            "inflection_type",
+            "inflection_form",
+            "lemma_reading",
+            "lemma",
+            "expression",
+            "reading",
+            "expression_base",
+            "reading_base",
+        ],
+    }
+}
+
+/// A single loaded dictionary and the worker that tokenizes against it.
+///
+/// `ouroboros` ties `Worker<'this>` to the owned `Tokenizer`, so each
+/// dictionary gets its own self-referencing cell rather than sharing one
+/// across dictionaries.
 #[ouroboros::self_referencing]
-struct VibratoWorker {
+struct DictWorker {
+    source_path: PathBuf,
+    /// User lexicon rows already merged into this dictionary, kept around
+    /// so the *next* `load_user_dictionary` call can rebuild on top of them
+    /// instead of starting over from the compiled dictionary alone.
+    user_lexicon_rows: Vec<String>,
     mmap: Mmap,
-    tokenizer: Tokenizer,
+    tokenizer: Arc<Tokenizer>,
     #[borrows(tokenizer)]
     #[covariant]
     inner: Worker<'this>,
 }
 
-impl VibratoWorker {
+impl DictWorker {
     fn create(p: PathBuf) -> Result<Self> {
-        let out = format!("{}.dump", p.to_string_lossy());
-        if !p.ends_with(".dump") {
-            let file = std::fs::File::open(p.as_path())?;
+        Self::create_with_user_lexicon(p, Vec::new())
+    }
 
+    /// Builds a `DictWorker`, optionally merging `user_lexicon_rows` into
+    /// the dictionary before it's handed to the `Tokenizer`.
+    ///
+    /// `p` is the compressed `.dic.zst` source; the decompressed dump is
+    /// cached alongside it as `{p}.dump` and reused on later calls (e.g.
+    /// rebuilds for a new user lexicon) instead of re-decompressing every
+    /// time.
+    fn create_with_user_lexicon(p: PathBuf, user_lexicon_rows: Vec<String>) -> Result<Self> {
+        let out = PathBuf::from(format!("{}.dump", p.to_string_lossy()));
+        let dump_path = if p.extension().is_some_and(|ext| ext == "dump") {
+            p.clone()
+        } else if out.exists() {
+            out
+        } else {
+            let file = std::fs::File::open(p.as_path())?;
             let mut decoder = ruzstd::StreamingDecoder::new(file)?;
-            let mut out = std::fs::File::create(&out)?;
-            std::io::copy(&mut decoder, &mut out)?;
-        }
-        let dump = std::fs::File::open(&out)?;
+            let mut out_file = std::fs::File::create(&out)?;
+            std::io::copy(&mut decoder, &mut out_file)?;
+            out
+        };
+        let dump = std::fs::File::open(&dump_path)?;
         let mmap = unsafe { Mmap::map(&dump)? };
-        let dict = Dictionary::read(&mmap)?;
-        let tokenizer = Tokenizer::new(dict);
-        Ok(VibratoWorkerBuilder {
+        let mut dict = Dictionary::read(&mmap)?;
+        if !user_lexicon_rows.is_empty() {
+            let csv = user_lexicon_rows.join("\n");
+            dict = dict.reset_user_lexicon_from_reader(Some(csv.as_bytes()))?;
+        }
+        let tokenizer = Arc::new(Tokenizer::new(dict));
+        Ok(DictWorkerBuilder {
+            source_path: p,
+            user_lexicon_rows,
             mmap,
             tokenizer,
             inner_builder: |tokenizer| tokenizer.new_worker(),
@@ -43,65 +111,274 @@ impl VibratoWorker {
         .build())
     }
 
-    fn tokenize(&mut self, s: &str) -> Result<Vec<Value>> {
+    /// Rebuilds this dictionary's tokenizer with `new_rows` appended to
+    /// every user lexicon row merged in so far. `ouroboros` ties
+    /// `Worker<'this>` to the owned `Tokenizer`, so there's no way to
+    /// attach a user lexicon in place — the caller is expected to drop the
+    /// old `DictWorker` in favor of the one this returns.
+    fn rebuild_with_additional_rows(&self, new_rows: &[String]) -> Result<Self> {
+        let mut rows = self.borrow_user_lexicon_rows().clone();
+        rows.extend(new_rows.iter().cloned());
+        Self::create_with_user_lexicon(self.borrow_source_path().clone(), rows)
+    }
+
+    /// Clones the handle to this dictionary's `Tokenizer` so other threads
+    /// can spawn their own `Worker`s without touching this `DictWorker`'s
+    /// own (single-threaded) one.
+    fn tokenizer_handle(&self) -> Arc<Tokenizer> {
+        Arc::clone(self.borrow_tokenizer())
+    }
+
+    /// Tokenizes `s` against this dictionary, returning one feature
+    /// breakdown per token (no wrapping `source` entry — the caller owns
+    /// that since it's shared across every dictionary's breakdown).
+    fn tokenize(&mut self, s: &str, schema: &[&str]) -> Result<Vec<Value>> {
         self.with_inner_mut(|worker| {
             worker.reset_sentence(s);
             worker.tokenize();
         });
-        let tokens = self.borrow_inner().token_iter();
-        let mut out = Vec::new();
-        out.push(json!({"source": s}));
-        for tk in tokens {
-            const DATA: &[&str] = &[
-                "pos",
-                "pos2",
-                "pos3",
-                "pos4",
-                "inflection_type",
-                "inflection_form",
-                "lemma_reading",
-                "lemma",
-                "expression",
-                "reading",
-                "expression_base",
-                "reading_base",
-            ];
-            let feature_spl = tk.feature().split(',');
-            let surface = tk.surface();
-            let info = feature_spl.flat_map(|f| f.split('-')).map(|t| {
-                if t == "*" {
-                    None
-                } else {
-                    Some(t.to_string())
-                }
-            });
-            let mut value = vec![("source".to_string(), Some(surface.to_string()))];
-            value.extend(
-                DATA.into_iter()
-                    .map(ToString::to_string)
-                    .zip(info)
-                    .collect::<Vec<(String, Option<String>)>>(),
-            );
-            out.push(serde_json::to_value(&value)?);
+        self.borrow_inner()
+            .token_iter()
+            .map(|tk| token_value(tk.surface(), tk.feature(), schema))
+            .collect()
+    }
+}
+
+/// Merges `rows` into `dict_worker`'s user lexicon in as few dictionary
+/// rebuilds as possible: the whole slice is tried as one batch first, and
+/// only rejected by vibrato does this fall back to bisecting the slice and
+/// recursing into each half — so per-row acceptance is still reportable,
+/// but a clean load costs one rebuild and a single bad row among N costs
+/// O(log N) rather than O(N).
+fn merge_rows(
+    dict_worker: DictWorker,
+    rows: &[(usize, &str)],
+    rejected: &mut Vec<(usize, String)>,
+) -> (DictWorker, usize) {
+    if rows.is_empty() {
+        return (dict_worker, 0);
+    }
+    let batch: Vec<String> = rows.iter().map(|(_, row)| row.to_string()).collect();
+    match dict_worker.rebuild_with_additional_rows(&batch) {
+        Ok(rebuilt) => (rebuilt, rows.len()),
+        Err(e) if rows.len() == 1 => {
+            rejected.push((rows[0].0, e.to_string()));
+            (dict_worker, 0)
+        }
+        Err(_) => {
+            let (left, right) = rows.split_at(rows.len() / 2);
+            let (dict_worker, added_left) = merge_rows(dict_worker, left, rejected);
+            let (dict_worker, added_right) = merge_rows(dict_worker, right, rejected);
+            (dict_worker, added_left + added_right)
+        }
+    }
+}
+
+/// Builds the JSON breakdown for a single token, mapping its `feature()`
+/// string onto `schema`'s column names (repo convention: `*` means "no
+/// value for this column", surfaced here as `null` rather than the literal
+/// string).
+fn token_value(surface: &str, feature: &str, schema: &[&str]) -> Result<Value> {
+    let info = feature.split(',').flat_map(|f| f.split('-')).map(|t| {
+        if t == "*" {
+            None
+        } else {
+            Some(t.to_string())
+        }
+    });
+    let mut value = vec![("source".to_string(), Some(surface.to_string()))];
+    value.extend(
+        schema
+            .iter()
+            .map(ToString::to_string)
+            .zip(info)
+            .collect::<Vec<(String, Option<String>)>>(),
+    );
+    Ok(serde_json::to_value(&value)?)
+}
+
+/// Assembles the `{"source": s, <dict>: [tokens...], ...}` span value shared
+/// by the sequential (`VibratoWorker::tokenize`) and parallel
+/// (`tokenize_span`) tokenization paths, so a future schema/ordering tweak
+/// only has to be made in one place.
+fn build_span_value<'a>(s: &str, per_dict: impl IntoIterator<Item = (&'a str, Vec<Value>)>) -> Value {
+    let mut out = serde_json::Map::new();
+    out.insert("source".to_string(), json!(s));
+    for (name, tokens) in per_dict {
+        out.insert(name.to_string(), json!(tokens));
+    }
+    Value::Object(out)
+}
+
+/// Tokenizes `s` against every dictionary in `dict_handles`, each on its own
+/// fresh `Worker`. Used by the parallel pool in `tokenize_lines`, where
+/// spans are processed on worker threads rather than through a
+/// `VibratoWorker`'s own (single, shared) `DictWorker`s.
+fn tokenize_span(dict_handles: &[(String, Arc<Tokenizer>)], s: &str) -> Result<Value> {
+    let mut per_dict = Vec::with_capacity(dict_handles.len());
+    for (name, tokenizer) in dict_handles {
+        let mut worker = tokenizer.new_worker();
+        worker.reset_sentence(s);
+        worker.tokenize();
+        let tokens = worker
+            .token_iter()
+            .map(|tk| token_value(tk.surface(), tk.feature(), feature_schema(name)))
+            .collect::<Result<Vec<Value>>>()?;
+        per_dict.push((name.as_str(), tokens));
+    }
+    Ok(build_span_value(s, per_dict))
+}
+
+/// Splits `segments` into at most `n_threads` contiguous chunks (of
+/// `ceil(len / n_threads)` segments each, the last possibly shorter) and tokenizes each
+/// chunk on its own thread, then reassembles the results in their original
+/// order. `n_threads` is assumed to be `>= 2`; smaller inputs are handled
+/// sequentially by the caller.
+fn tokenize_parallel(
+    dict_names: &[String],
+    dict_handles: &[(String, Arc<Tokenizer>)],
+    segments: &[&str],
+    n_threads: usize,
+) -> Result<Vec<Value>> {
+    let chunk_size = segments.len().div_ceil(n_threads).max(1);
+    let handles: Vec<_> = segments
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let chunk: Vec<String> = chunk.iter().map(|s| s.to_string()).collect();
+            let dict_handles = dict_handles.to_vec();
+            let dict_names = dict_names.to_vec();
+            std::thread::spawn(move || -> Result<Vec<Value>> {
+                chunk
+                    .iter()
+                    .map(|part| {
+                        if part.trim().is_empty() {
+                            Ok(generate_dummy_data(part, &dict_names))
+                        } else {
+                            tokenize_span(&dict_handles, part)
+                        }
+                    })
+                    .collect()
+            })
+        })
+        .collect();
+
+    let mut out = Vec::with_capacity(segments.len());
+    for handle in handles {
+        let chunk_result = handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("tokenizer worker thread panicked"))??;
+        out.extend(chunk_result);
+    }
+    Ok(out)
+}
+
+/// Holds every dictionary configured for this worker and tokenizes text
+/// against all of them at once, so a single `parse_text` call returns each
+/// dictionary's analysis of the same span.
+struct VibratoWorker {
+    dictionaries: Vec<(String, DictWorker)>,
+}
+
+impl VibratoWorker {
+    /// Loads every dictionary in `dicts`, skipping (with a logged warning)
+    /// any whose file is missing or fails to load, so one bad entry in the
+    /// configured set doesn't take the whole worker down.
+    fn create(dicts: &[(String, PathBuf)]) -> Result<Self> {
+        let mut dictionaries = Vec::with_capacity(dicts.len());
+        for (name, path) in dicts {
+            match DictWorker::create(path.clone()) {
+                Ok(dict_worker) => dictionaries.push((name.clone(), dict_worker)),
+                Err(e) => log::warn!(
+                    "Skipping dictionary `{name}` ({}): {e}",
+                    path.to_string_lossy()
+                ),
+            }
         }
-        Ok(out)
+        Ok(Self { dictionaries })
+    }
+
+    fn dictionary_names(&self) -> Vec<String> {
+        self.dictionaries.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    fn tokenize(&mut self, s: &str) -> Result<Value> {
+        let mut per_dict = Vec::with_capacity(self.dictionaries.len());
+        for (name, dict_worker) in &mut self.dictionaries {
+            let tokens = dict_worker.tokenize(s, feature_schema(name))?;
+            per_dict.push((name.as_str(), tokens));
+        }
+        Ok(build_span_value(s, per_dict))
+    }
+
+    /// Merges `csv` into `dict_name`'s lexicon on top of any user entries
+    /// already loaded for it, and swaps in the rebuilt `DictWorker` so
+    /// subsequent `tokenize`/`tokenize_lines` calls see the new terms.
+    ///
+    /// Rows that don't look like `surface,left_id,right_id,cost,...` are
+    /// rejected up front without ever reaching vibrato. The rest are merged
+    /// in a single rebuild (see `merge_rows`), falling back to bisecting
+    /// only if vibrato rejects the batch, so a realistic term list costs
+    /// one rebuild rather than one per row; `added` counts only rows
+    /// vibrato actually accepted, and every rejection (shallow or from
+    /// vibrato) is reported back to the caller.
+    fn load_user_dictionary(
+        &mut self,
+        dict_name: &str,
+        csv: &str,
+    ) -> Result<(usize, Vec<(usize, String)>)> {
+        let idx = self
+            .dictionaries
+            .iter()
+            .position(|(name, _)| name == dict_name)
+            .ok_or_else(|| anyhow::anyhow!("unknown dictionary `{dict_name}`"))?;
+
+        let (valid_rows, mut rejected) = validate_user_lexicon_rows(csv);
+        let (name, dict_worker) = self.dictionaries.remove(idx);
+        let (dict_worker, added) = merge_rows(dict_worker, &valid_rows, &mut rejected);
+        self.dictionaries.insert(idx, (name, dict_worker));
+        rejected.sort_by_key(|(line, _)| *line);
+        Ok((added, rejected))
     }
 
-    fn tokenize_lines(&mut self, s: &str) -> Result<Vec<Value>> {
-        let mut res = Vec::new();
-        for line in s.lines() {
-            // const SKIP_PAT: &'static str = r"[\s\u30fb]";
-            // let a_reg = Regex::new(SKIP_PAT)?;
-            // let n_reg = Regex::new(&format!(r"{0}|.*?(?={0})|.*", SKIP_PAT))?;
-            for part in split_words(line) {
+    /// Tokenizes every word span in `s` against all configured dictionaries.
+    ///
+    /// `max_parallelism` caps how many threads may share the work; `None`
+    /// defaults to the available parallelism, and `Some(0)` or `Some(1)`
+    /// forces the sequential path (worthwhile for small inputs, where
+    /// spawning threads costs more than it saves).
+    fn tokenize_lines(&mut self, s: &str, max_parallelism: Option<usize>) -> Result<Vec<Value>> {
+        let dict_names = self.dictionary_names();
+        // const SKIP_PAT: &'static str = r"[\s\u30fb]";
+        // let a_reg = Regex::new(SKIP_PAT)?;
+        // let n_reg = Regex::new(&format!(r"{0}|.*?(?={0})|.*", SKIP_PAT))?;
+        let segments: Vec<&str> = s.lines().flat_map(split_words).collect();
+
+        let available = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let n_threads = max_parallelism
+            .unwrap_or(available)
+            .clamp(1, available)
+            .min(segments.len().max(1));
+
+        if n_threads <= 1 {
+            let mut res = Vec::with_capacity(segments.len());
+            for part in segments {
                 if part.trim().is_empty() {
-                    res.push(generate_dummy_data(part));
+                    res.push(generate_dummy_data(part, &dict_names));
                     continue;
                 }
-                res.extend(self.tokenize(part)?);
+                res.push(self.tokenize(part)?);
             }
+            return Ok(res);
         }
-        Ok(res)
+
+        let dict_handles: Vec<(String, Arc<Tokenizer>)> = self
+            .dictionaries
+            .iter()
+            .map(|(name, dict_worker)| (name.clone(), dict_worker.tokenizer_handle()))
+            .collect();
+        tokenize_parallel(&dict_names, &dict_handles, &segments, n_threads)
     }
 }
 
@@ -157,12 +434,93 @@ fn split_words(s: &str) -> impl Iterator<Item = &str> {
     })
 }
 
-fn generate_dummy_data(s: &str) -> Value {
+/// Splits a user lexicon CSV (`surface,left_id,right_id,cost,feature...`)
+/// into `(1-based line, row)` pairs that look well-formed and
+/// `(1-based line, reason)` pairs for rows that were rejected before they
+/// ever reach vibrato.
+fn validate_user_lexicon_rows(csv: &str) -> (Vec<(usize, &str)>, Vec<(usize, String)>) {
+    let mut valid = Vec::new();
+    let mut rejected = Vec::new();
+    for (i, line) in csv.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() < 4 {
+            rejected.push((
+                i + 1,
+                "expected at least 4 comma-separated fields".to_string(),
+            ));
+            continue;
+        }
+        if fields[1..=3].iter().any(|f| f.parse::<i64>().is_err()) {
+            rejected.push((
+                i + 1,
+                "left_id, right_id, and cost must be integers".to_string(),
+            ));
+            continue;
+        }
+        valid.push((i + 1, line));
+    }
+    (valid, rejected)
+}
+
+/// Strips control characters (other than `\n`/`\t`, which `tokenize_lines`
+/// still splits on) out of untrusted input text before it reaches the
+/// tokenizer, so a malicious client can't smuggle bytes that corrupt the log
+/// file or confuse vibrato's lattice. Returns whether anything was removed.
+fn sanitize_text(s: &str) -> (String, bool) {
+    let mut stripped = false;
+    let cleaned = s
+        .chars()
+        .filter(|c| {
+            let keep = !c.is_control() || *c == '\n' || *c == '\t';
+            stripped |= !keep;
+            keep
+        })
+        .collect();
+    (cleaned, stripped)
+}
+
+fn generate_dummy_data(s: &str, dict_names: &[String]) -> Value {
+    let mut out = serde_json::Map::new();
+    out.insert("source".to_string(), json!(s.to_string()));
+    for name in dict_names {
+        out.insert(name.clone(), Value::Null);
+    }
+    Value::Object(out)
+}
+
+/// Wire encoding used to (de)serialize a single native-messaging frame.
+///
+/// The u32 length prefix is shared by both encodings; only the body bytes
+/// differ. Negotiated per-connection via `get_version`'s `format` param and
+/// advertised back in its `supported_formats` field.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Encoding {
+    Json,
+    MsgPack,
+}
+
+impl Encoding {
+    const SUPPORTED: &'static [&'static str] = &["json", "msgpack"];
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            "msgpack" => Some(Self::MsgPack),
+            _ => None,
+        }
+    }
+}
+
+/// Builds a `{"sequence": ..., "error": {"code": ..., "message": ...}}`
+/// response. Every dispatch branch in `handle_message` returns one of these
+/// instead of panicking, so a malformed request can't take the worker down.
+fn error_response(sequence: Value, code: &str, message: impl Into<String>) -> Value {
     json!({
-        "source": s.to_string(),
-        "ipadic": null,
-        "ipadic-neologd": null,
-        "unidic-mecab-translate": null,
+        "sequence": sequence,
+        "error": {"code": code, "message": message.into()},
     })
 }
 
@@ -172,7 +530,7 @@ fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
     Ok(u32::from_ne_bytes(buf))
 }
 
-fn get_message<R: BufRead + Read>(r: &mut R) -> Result<Option<Value>> {
+fn get_message<R: BufRead + Read>(r: &mut R, encoding: Encoding) -> Result<Option<Value>> {
     if r.fill_buf()?.is_empty() {
         return Ok(None);
     }
@@ -181,16 +539,32 @@ fn get_message<R: BufRead + Read>(r: &mut R) -> Result<Option<Value>> {
     let mut buf = vec![0u8; len as usize];
     r.read_exact(&mut buf)?;
 
-    let s = String::from_utf8(buf)?;
-    log::info!("Received msg: `{s}`");
-    Ok(Some(Value::from_str(&s)?))
+    // Logged with control bytes stripped (see `sanitize_text`) so a
+    // malicious frame can't corrupt the log file; the `Value` returned to
+    // the caller is parsed from the untouched `buf`/`s`.
+    let value = match encoding {
+        Encoding::Json => {
+            let s = String::from_utf8(buf)?;
+            log::info!("Received msg: `{}`", sanitize_text(&s).0);
+            Value::from_str(&s)?
+        }
+        Encoding::MsgPack => {
+            let value: Value = rmp_serde::from_slice(&buf)?;
+            log::info!("Received msgpack msg: `{}`", sanitize_text(&value.to_string()).0);
+            value
+        }
+    };
+    Ok(Some(value))
 }
 
-fn send_message<W: Write>(w: &mut W, msg: Value) -> Result<()> {
-    let s = msg.to_string();
-    w.write(&(s.len() as u32).to_ne_bytes())?;
-    w.write_all(s.as_bytes())?;
-    log::info!("Sending {} bytes", s.len());
+fn send_message<W: Write>(w: &mut W, msg: Value, encoding: Encoding) -> Result<()> {
+    let bytes = match encoding {
+        Encoding::Json => msg.to_string().into_bytes(),
+        Encoding::MsgPack => rmp_serde::to_vec(&msg)?,
+    };
+    w.write(&(bytes.len() as u32).to_ne_bytes())?;
+    w.write_all(&bytes)?;
+    log::info!("Sending {} bytes", bytes.len());
     w.flush()?;
     Ok(())
 }
@@ -224,43 +598,206 @@ fn do_stuff() -> anyhow::Result<()> {
     setup_logger().unwrap();
     log::info!("Beginning...");
 
-    let dict = PathBuf::from(String::from("./system.dic.zst"));
-    let mut worker = VibratoWorker::create(dict)?;
+    let dicts = vec![
+        ("ipadic".to_string(), PathBuf::from("./ipadic.dic.zst")),
+        (
+            "ipadic-neologd".to_string(),
+            PathBuf::from("./ipadic-neologd.dic.zst"),
+        ),
+        (
+            "unidic-mecab-translate".to_string(),
+            PathBuf::from("./system.dic.zst"),
+        ),
+    ];
+    let mut worker = VibratoWorker::create(&dicts)?;
 
     let mut sin = stdin().lock();
     let mut sout = stdout();
+    // Native messaging always bootstraps in JSON; a client switches the
+    // connection to MessagePack by sending `"format": "msgpack"` on
+    // `get_version`, after which both directions use the negotiated encoding.
+    let mut encoding = Encoding::Json;
     loop {
-        if let Some(msg) = get_message(&mut sin)? {
-            match msg.get("action") {
-                Some(req) if req == "get_version" => {
-                    let sequence = msg["sequence"].clone();
-                    let response = json!({
-                        "sequence": sequence,
-                        "data": {"version": 1},
-                    });
-                    log::info!("Sent {response}");
-                    send_message(&mut sout, response)?;
-                    log::info!("Message sent!")
+        let msg = match get_message(&mut sin, encoding) {
+            Ok(None) => break,
+            Ok(Some(msg)) => msg,
+            Err(e) => {
+                log::error!("Failed to read message: {e}");
+                let res = error_response(Value::Null, "invalid_frame", e.to_string());
+                send_message(&mut sout, res, encoding)?;
+                continue;
+            }
+        };
+        let (res, next_encoding) = handle_message(&mut worker, &msg);
+        send_message(&mut sout, res, encoding)?;
+        // Applied only after the negotiating response itself has gone out
+        // in the *old* encoding, so a client switching formats on
+        // `get_version` can still decode the reply that advertises
+        // `supported_formats`.
+        if let Some(e) = next_encoding {
+            encoding = e;
+        }
+    }
+    Ok(())
+}
+
+/// Dispatches a single decoded request to a response, never panicking: every
+/// branch below returns either `{"data": ...}` or `{"error": ...}`, so a
+/// malformed request can't take down the rest of the connection. The second
+/// return value is the encoding a `format` param asked to switch to, if any;
+/// the caller applies it only after sending this response in the current
+/// encoding.
+fn handle_message(worker: &mut VibratoWorker, msg: &Value) -> (Value, Option<Encoding>) {
+    let sequence = msg["sequence"].clone();
+
+    let next_encoding = msg["params"]["format"].as_str().and_then(|name| {
+        let encoding = Encoding::from_name(name);
+        if encoding.is_none() {
+            log::warn!("Unknown format `{name}`, keeping current encoding");
+        }
+        encoding
+    });
+
+    let res = match msg["action"].as_str() {
+        Some("get_version") => json!({
+            "sequence": sequence,
+            "data": {"version": 1, "supported_formats": Encoding::SUPPORTED},
+        }),
+        Some("list_dictionaries") => {
+            let data: Vec<Value> = worker
+                .dictionaries
+                .iter()
+                .map(|(name, _)| json!({"name": name, "features": feature_schema(name)}))
+                .collect();
+            json!({
+                "sequence": sequence,
+                "data": data,
+            })
+        }
+        Some("load_user_dictionary") => {
+            let Some(dict_name) = msg["params"]["dictionary"].as_str() else {
+                return (
+                    error_response(
+                        sequence,
+                        "invalid_params",
+                        "load_user_dictionary requires `params.dictionary`",
+                    ),
+                    next_encoding,
+                );
+            };
+            let csv = if let Some(inline) = msg["params"]["inline"].as_str() {
+                inline.to_string()
+            } else if let Some(path) = msg["params"]["path"].as_str() {
+                match std::fs::read_to_string(path) {
+                    Ok(csv) => csv,
+                    Err(e) => {
+                        return (
+                            error_response(
+                                sequence,
+                                "io_error",
+                                format!("failed to read `{path}`: {e}"),
+                            ),
+                            next_encoding,
+                        )
+                    }
                 }
-                Some(req) if req == "parse_text" => {
-                    log::info!("Asked to parse text...");
-                    let text = msg["params"]["text"].as_str().unwrap_or_else(|| {
-                        log::info!("Unwrapped!");
-                        panic!();
-                    });
-                    let tokenized = serde_json::to_value(worker.tokenize_lines(text)?)?;
-                    log::info!("Tokens: {tokenized}");
-                    let res = json!({
-                        "sequence": msg["sequence"],
+            } else {
+                return (
+                    error_response(
+                        sequence,
+                        "invalid_params",
+                        "load_user_dictionary requires `params.inline` or `params.path`",
+                    ),
+                    next_encoding,
+                );
+            };
+            match worker.load_user_dictionary(dict_name, &csv) {
+                Ok((added, rejected)) => json!({
+                    "sequence": sequence,
+                    "data": {
+                        "dictionary": dict_name,
+                        "added": added,
+                        "rejected": rejected
+                            .into_iter()
+                            .map(|(row, reason)| json!({"row": row, "reason": reason}))
+                            .collect::<Vec<_>>(),
+                    },
+                }),
+                Err(e) => error_response(sequence, "dictionary_load_failed", e.to_string()),
+            }
+        }
+        Some("parse_text") => {
+            let Some(raw_text) = msg["params"]["text"].as_str() else {
+                return (
+                    error_response(
+                        sequence,
+                        "invalid_params",
+                        "parse_text requires `params.text`",
+                    ),
+                    next_encoding,
+                );
+            };
+            let (text, stripped_control_chars) = sanitize_text(raw_text);
+            let max_parallelism = msg["params"]["max_parallelism"]
+                .as_u64()
+                .map(|n| n as usize);
+            match worker.tokenize_lines(&text, max_parallelism) {
+                Ok(tokenized) => {
+                    let mut res = json!({
+                        "sequence": sequence,
                         "data": tokenized,
                     });
-                    send_message(&mut sout, res)?;
-                }
-                _ => {
-                    log::error!("Unknown request");
-                    unreachable!();
+                    if stripped_control_chars {
+                        res["warning"] =
+                            json!("control characters were stripped from `params.text`");
+                    }
+                    res
                 }
+                Err(e) => error_response(sequence, "tokenize_failed", e.to_string()),
             }
         }
+        Some(other) => {
+            error_response(sequence, "unknown_action", format!("unknown action `{other}`"))
+        }
+        None => error_response(sequence, "missing_action", "request is missing an `action`"),
+    };
+    (res, next_encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Round-trips `msg` through `send_message`/`get_message` under `encoding`
+    /// and returns what the other side of the pipe would decode.
+    fn round_trip(msg: &Value, encoding: Encoding) -> Value {
+        let mut buf = Vec::new();
+        send_message(&mut buf, msg.clone(), encoding).unwrap();
+        let mut cursor = Cursor::new(buf);
+        get_message(&mut cursor, encoding).unwrap().unwrap()
+    }
+
+    #[test]
+    fn json_and_msgpack_clients_see_equivalent_token_data() {
+        // Drive real token/span assembly (the same `token_value` and
+        // `build_span_value` calls `DictWorker::tokenize`/`tokenize_span`
+        // make) rather than a hand-built `Value`, so this proves token data
+        // survives the wire round-trip, not just arbitrary JSON.
+        let schema = feature_schema("ipadic");
+        let token =
+            token_value("テスト", "名詞,一般,*,*,*,*,テスト,テスト,テスト", schema).unwrap();
+        let span = build_span_value("テスト", [("ipadic", vec![token])]);
+        let msg = json!({
+            "sequence": 1,
+            "data": span,
+        });
+
+        let via_json = round_trip(&msg, Encoding::Json);
+        let via_msgpack = round_trip(&msg, Encoding::MsgPack);
+
+        assert_eq!(via_json, msg);
+        assert_eq!(via_msgpack, msg);
+        assert_eq!(via_json, via_msgpack);
     }
 }